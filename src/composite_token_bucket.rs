@@ -0,0 +1,227 @@
+use std::fmt;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use crate::{
+    quota::Quota,
+    token_bucket::{Clock, NotUntil, SystemClock, TokenBucket},
+};
+
+/// A rate-limiter built from a Riot-style `"count:seconds,count:seconds,..."` spec,
+/// where a request is only admitted when every comma-separated window allows it.
+///
+/// This is the shape API rate-limit headers tend to come in (e.g. `"20:1,100:120"`
+/// for "20 requests per second, and 100 per two minutes"), so [`CompositeTokenBucket::parse`]
+/// lets callers build a limiter straight from those headers instead of hand-building
+/// a [`Quota`] per window.
+#[derive(Debug, Clone)]
+pub struct CompositeTokenBucket<C: Clock = SystemClock> {
+    buckets: Vec<TokenBucket<C>>,
+}
+
+/// An error parsing a `"count:seconds"` rate-limit spec.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CompositeParseError {
+    Empty,
+    MalformedPair(String),
+    InvalidCount(String),
+    InvalidSeconds(String),
+    ZeroCount(String),
+    ZeroSeconds(String),
+    DegenerateRate(String),
+}
+
+impl fmt::Display for CompositeParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompositeParseError::Empty => write!(f, "rate-limit spec is empty"),
+            CompositeParseError::MalformedPair(p) => {
+                write!(f, "expected \"count:seconds\", got {p:?}")
+            }
+            CompositeParseError::InvalidCount(c) => write!(f, "invalid count {c:?}"),
+            CompositeParseError::InvalidSeconds(s) => write!(f, "invalid seconds {s:?}"),
+            CompositeParseError::ZeroCount(p) => write!(f, "count must be nonzero in {p:?}"),
+            CompositeParseError::ZeroSeconds(p) => write!(f, "seconds must be nonzero in {p:?}"),
+            CompositeParseError::DegenerateRate(p) => {
+                write!(f, "{p:?} replenishes less than one micro, too coarse")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompositeParseError {}
+
+impl CompositeTokenBucket<SystemClock> {
+    /// Parses a spec like `"20:1,100:120"` into one [`TokenBucket`] per `count:seconds`
+    /// pair, each allowing `count` tokens to replenish over `seconds` seconds.
+    pub fn parse(spec: &str) -> Result<Self, CompositeParseError> {
+        Self::parse_with_clock(spec, SystemClock)
+    }
+}
+
+impl<C: Clock> CompositeTokenBucket<C> {
+    pub fn parse_with_clock(spec: &str, clock: C) -> Result<Self, CompositeParseError> {
+        if spec.trim().is_empty() {
+            return Err(CompositeParseError::Empty);
+        }
+
+        let mut buckets = Vec::new();
+        for raw_pair in spec.split(',') {
+            let pair = raw_pair.trim();
+            let (count_str, seconds_str) = pair
+                .split_once(':')
+                .ok_or_else(|| CompositeParseError::MalformedPair(pair.to_string()))?;
+            let count_str = count_str.trim();
+            let seconds_str = seconds_str.trim();
+
+            let count: u32 = count_str
+                .parse()
+                .map_err(|_| CompositeParseError::InvalidCount(count_str.to_string()))?;
+            let count = NonZeroU32::new(count)
+                .ok_or_else(|| CompositeParseError::ZeroCount(pair.to_string()))?;
+
+            let seconds: u64 = seconds_str
+                .parse()
+                .map_err(|_| CompositeParseError::InvalidSeconds(seconds_str.to_string()))?;
+            if seconds == 0 {
+                return Err(CompositeParseError::ZeroSeconds(pair.to_string()));
+            }
+
+            let replenish_1_per = Duration::from_secs(seconds) / count.get();
+            let quota = Quota::with_period(replenish_1_per)
+                .ok_or_else(|| CompositeParseError::DegenerateRate(pair.to_string()))?
+                .allow_burst(count);
+            buckets.push(TokenBucket::with_clock(quota, clock.clone()));
+        }
+
+        Ok(Self { buckets })
+    }
+
+    pub fn check_n(&self, n: u32) -> Result<(), NotUntil> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.check_n(n))
+            .reduce(Self::latest_rejection)
+            .expect("parse never produces an empty bucket list")
+    }
+
+    pub fn try_take_n(&mut self, n: u32) -> Result<(), NotUntil> {
+        let mut charged = Vec::with_capacity(self.buckets.len());
+        let res = self
+            .buckets
+            .iter_mut()
+            .map(|bucket| match bucket.try_take_n(n) {
+                Ok(()) => {
+                    charged.push(bucket);
+                    Ok(())
+                }
+                Err(not_until) => Err(not_until),
+            })
+            .reduce(Self::latest_rejection)
+            .expect("parse never produces an empty bucket list");
+
+        if res.is_err() {
+            for bucket in charged {
+                bucket.refund(n);
+            }
+        }
+        res
+    }
+
+    /// Combines two sub-bucket decisions the way [`Result::and`] would, except
+    /// that when both reject, the *later* `NotUntil` wins so callers wait long
+    /// enough to satisfy every window at once.
+    fn latest_rejection(a: Result<(), NotUntil>, b: Result<(), NotUntil>) -> Result<(), NotUntil> {
+        match (a, b) {
+            (Ok(()), Ok(())) => Ok(()),
+            (Err(not_until), Ok(())) | (Ok(()), Err(not_until)) => Err(not_until),
+            (Err(a), Err(b)) => {
+                if b.earliest_possible() > a.earliest_possible() {
+                    Err(b)
+                } else {
+                    Err(a)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::token_bucket::FakeClock;
+    use std::time::Instant;
+
+    #[test]
+    fn parse_builds_one_bucket_per_window() {
+        let bucket = CompositeTokenBucket::parse("20:1,100:120").unwrap();
+        assert_eq!(bucket.buckets.len(), 2);
+    }
+
+    #[test]
+    fn parse_tolerates_surrounding_whitespace() {
+        let bucket = CompositeTokenBucket::parse(" 20 : 1 , 100 : 120 ").unwrap();
+        assert_eq!(bucket.buckets.len(), 2);
+    }
+
+    #[test]
+    fn parse_rejects_empty_spec() {
+        assert_eq!(
+            CompositeTokenBucket::parse("  ").unwrap_err(),
+            CompositeParseError::Empty
+        );
+    }
+
+    #[test]
+    fn parse_rejects_zero_count() {
+        assert_eq!(
+            CompositeTokenBucket::parse("0:1").unwrap_err(),
+            CompositeParseError::ZeroCount("0:1".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_rejects_zero_seconds() {
+        assert_eq!(
+            CompositeTokenBucket::parse("5:0").unwrap_err(),
+            CompositeParseError::ZeroSeconds("5:0".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_rejects_malformed_pair() {
+        assert_eq!(
+            CompositeTokenBucket::parse("20").unwrap_err(),
+            CompositeParseError::MalformedPair("20".to_string())
+        );
+    }
+
+    #[test]
+    fn check_n_requires_every_window_to_admit() {
+        let bucket = CompositeTokenBucket::parse("20:1,5:1").unwrap();
+        assert_eq!(bucket.check_n(5), Ok(()));
+        assert!(bucket.check_n(6).is_err());
+    }
+
+    #[test]
+    fn try_take_n_is_all_or_nothing_across_windows() {
+        let mut bucket = CompositeTokenBucket::parse("20:1,5:1").unwrap();
+        assert_eq!(bucket.try_take_n(5), Ok(()));
+        assert!(bucket.try_take_n(1).is_err());
+    }
+
+    #[test]
+    fn rejection_waits_for_the_slowest_window() {
+        let clock = FakeClock::new(Instant::now());
+        let mut bucket = CompositeTokenBucket::parse_with_clock("4:1,2:4", clock.clone()).unwrap();
+        assert_eq!(bucket.try_take_n(2), Ok(()));
+        assert!(bucket.try_take_n(1).is_err());
+
+        clock.advance(Duration::from_secs(1));
+        // the 4:1 window has fully replenished, but the 2:4 window hasn't
+        assert!(bucket.try_take_n(1).is_err());
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(bucket.try_take_n(1), Ok(()));
+    }
+}
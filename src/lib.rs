@@ -1,6 +1,12 @@
-pub mod multi;
+pub mod composite_token_bucket;
+pub mod multi_quota;
 pub mod quota;
 pub mod token_bucket;
+pub mod token_bucket_multiple_sync;
+pub mod token_multi_ultimate;
+pub mod token_multi_ultimate_bounded;
+pub mod token_multi_ultimate_enum;
+pub mod waiter_queue;
 
 pub use nonzero_ext;
 pub use strum::IntoEnumIterator;
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::{
+    quota::Quota,
+    token_bucket::{Clock, NotUntil, SystemClock, TokenBucket},
+};
+
+/// Why a [`MultiQuota`] check was rejected: which resource ran out of budget, and when
+/// it will have enough again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultiQuotaRejection<R> {
+    pub resource: R,
+    pub not_until: NotUntil,
+}
+
+/// A rate limiter over several resources at once (e.g. request count *and* payload
+/// bytes), admitting a request only when every resource it costs has budget for it.
+///
+/// This is the twin-token-bucket shape used for throttling on more than one quantity
+/// simultaneously: instead of juggling one [`TokenBucket`] per resource and manually
+/// rolling back the others when one rejects, `MultiQuota` does the all-or-nothing
+/// bookkeeping itself and reports which resource was the limiting one.
+pub struct MultiQuota<R: Eq + Hash, C: Clock = SystemClock> {
+    buckets: HashMap<R, TokenBucket<C>>,
+}
+
+impl<R: Eq + Hash + Clone> MultiQuota<R, SystemClock> {
+    pub fn new(quotas: impl IntoIterator<Item = (R, Quota)>) -> Self {
+        Self::with_clock(quotas, SystemClock)
+    }
+}
+
+impl<R: Eq + Hash + Clone, C: Clock> MultiQuota<R, C> {
+    pub fn with_clock(quotas: impl IntoIterator<Item = (R, Quota)>, clock: C) -> Self {
+        let buckets = quotas
+            .into_iter()
+            .map(|(resource, quota)| (resource, TokenBucket::with_clock(quota, clock.clone())))
+            .collect();
+        Self { buckets }
+    }
+
+    pub fn check(&self, costs: &[(R, u32)]) -> Result<(), MultiQuotaRejection<R>> {
+        for (resource, cost) in costs {
+            let bucket = self
+                .buckets
+                .get(resource)
+                .expect("Do not use a resource that is not configured");
+            if let Err(not_until) = bucket.check_n(*cost) {
+                return Err(MultiQuotaRejection {
+                    resource: resource.clone(),
+                    not_until,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn try_take(&mut self, costs: &[(R, u32)]) -> Result<(), MultiQuotaRejection<R>> {
+        let mut charged = Vec::with_capacity(costs.len());
+        for (resource, cost) in costs {
+            let bucket = self
+                .buckets
+                .get_mut(resource)
+                .expect("Do not use a resource that is not configured");
+            match bucket.try_take_n(*cost) {
+                Ok(()) => charged.push((resource.clone(), *cost)),
+                Err(not_until) => {
+                    for (resource, cost) in charged {
+                        self.buckets.get_mut(&resource).unwrap().refund(cost);
+                    }
+                    return Err(MultiQuotaRejection {
+                        resource: resource.clone(),
+                        not_until,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+    enum Resource {
+        Requests,
+        Bytes,
+    }
+
+    fn limiter() -> MultiQuota<Resource> {
+        MultiQuota::new([
+            (Resource::Requests, Quota::per_second(nonzero!(100u32))),
+            (Resource::Bytes, Quota::per_second(nonzero!(1024u32 * 10))),
+        ])
+    }
+
+    #[test]
+    fn admits_when_every_resource_has_budget() {
+        let mut limiter = limiter();
+        assert_eq!(
+            limiter.try_take(&[(Resource::Requests, 1), (Resource::Bytes, 2048)]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_and_names_the_limiting_resource() {
+        let mut limiter = limiter();
+        let err = limiter
+            .try_take(&[(Resource::Requests, 1), (Resource::Bytes, 1024 * 20)])
+            .unwrap_err();
+        assert_eq!(err.resource, Resource::Bytes);
+    }
+
+    #[test]
+    fn rejection_on_a_later_resource_refunds_earlier_debits() {
+        let mut limiter = limiter();
+        assert!(limiter
+            .try_take(&[(Resource::Requests, 50), (Resource::Bytes, 1024 * 20)])
+            .is_err());
+        // the Requests debit from the failed batch above must have been refunded
+        assert_eq!(
+            limiter.try_take(&[(Resource::Requests, 100), (Resource::Bytes, 1)]),
+            Ok(())
+        );
+    }
+}
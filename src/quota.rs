@@ -33,6 +33,9 @@ use std::time::Duration;
 pub struct Quota {
     pub(crate) max_burst: NonZeroU32,
     pub(crate) replenish_1_per: Duration,
+    pub(crate) burst_window: Option<(Duration, NonZeroU32)>,
+    pub(crate) strict_sliding_window: bool,
+    pub(crate) fixed_window: bool,
 }
 
 /// Constructors for Quotas
@@ -45,6 +48,9 @@ impl Quota {
         Quota {
             max_burst,
             replenish_1_per: Duration::from_micros(replenish_interval_micros as u64),
+            burst_window: None,
+            strict_sliding_window: false,
+            fixed_window: false,
         }
     }
 
@@ -56,6 +62,9 @@ impl Quota {
         Quota {
             max_burst,
             replenish_1_per: Duration::from_micros(replenish_interval_micros as u64),
+            burst_window: None,
+            strict_sliding_window: false,
+            fixed_window: false,
         }
     }
 
@@ -67,9 +76,36 @@ impl Quota {
         Quota {
             max_burst,
             replenish_1_per: Duration::from_micros(replenish_interval_micros as u64),
+            burst_window: None,
+            strict_sliding_window: false,
+            fixed_window: false,
         }
     }
 
+    /// Construct a quota that allows `max_tokens` cells, replenishing all of them over
+    /// `replenish_all_every` (so `replenish_1_per` is `replenish_all_every / max_tokens`).
+    ///
+    /// This states a rate directly, e.g. `Quota::new(nonzero!(4u32), Duration::from_secs(2))`
+    /// for "4 tokens per 2 seconds", instead of picking a base unit like [`per_second`](#method.per_second)
+    /// and then calling [`allow_burst`](#method.allow_burst), which rounds each helper to its own
+    /// unit before combining them.
+    ///
+    /// Returns `None` if `replenish_all_every` divided across `max_tokens` cells would round
+    /// down to a zero-length replenish interval.
+    pub fn new(max_tokens: NonZeroU32, replenish_all_every: Duration) -> Option<Quota> {
+        let replenish_interval_micros = replenish_all_every.as_micros() / max_tokens.get() as u128;
+        if replenish_interval_micros == 0 {
+            return None;
+        }
+        Some(Quota {
+            max_burst: max_tokens,
+            replenish_1_per: Duration::from_micros(replenish_interval_micros as u64),
+            burst_window: None,
+            strict_sliding_window: false,
+            fixed_window: false,
+        })
+    }
+
     /// Construct a quota that replenishes one cell in a given
     /// interval.
     ///
@@ -86,6 +122,9 @@ impl Quota {
             Some(Quota {
                 max_burst: nonzero!(1u32),
                 replenish_1_per,
+                burst_window: None,
+                strict_sliding_window: false,
+                fixed_window: false,
             })
         }
     }
@@ -95,6 +134,67 @@ impl Quota {
     pub const fn allow_burst(self, max_burst: NonZeroU32) -> Quota {
         Quota { max_burst, ..self }
     }
+
+    /// Additionally bounds how many cells may be let through in any trailing window of
+    /// `burst_duration`, independent of the steady-rate replenishment above.
+    ///
+    /// Without this, a bucket that has sat idle can release its entire [`burst_size`](#method.burst_size)
+    /// instantaneously, since the steady-rate check only limits the *average* rate, not the shape
+    /// of a single spike. This bounds that spike: no more than `burst_limit` cells within any
+    /// trailing `burst_duration`, on top of the existing steady-rate limit.
+    pub const fn with_burst_window(self, burst_duration: Duration, burst_limit: NonZeroU32) -> Quota {
+        Quota {
+            burst_window: Some((burst_duration, burst_limit)),
+            ..self
+        }
+    }
+
+    /// Switches this quota to sliding-window enforcement: a request is admitted only while
+    /// the estimated number of cells in the trailing window of
+    /// [`burst_size_replenished_in`](#method.burst_size_replenished_in) length, plus the
+    /// request's own cells, stays within [`burst_size`](#method.burst_size).
+    ///
+    /// Without this, the steady replenishment model can let more than `burst_size` cells
+    /// through within the first window, because early cells replenish before the window
+    /// is over (e.g. `Quota::per_minute(n)` can admit more than `n` cells inside the first
+    /// minute). This trades that for an approximation (two fixed-window counters) rather
+    /// than an exact trailing-window count, so it costs O(1) state per key instead of a
+    /// full timestamp log. The previous window's count is weighted by how much of that window
+    /// still overlaps the trailing one, so the exact number of cells in a trailing window can
+    /// exceed `burst_size` when the previous window's cells were admitted near its end.
+    pub const fn enforce_sliding_window(self) -> Quota {
+        Quota {
+            strict_sliding_window: true,
+            ..self
+        }
+    }
+
+    /// Construct a quota whose full `max_burst` allowance resets instantaneously at the end
+    /// of each `period`, rather than continuously replenishing one cell at a time.
+    ///
+    /// This is the "X requests per Y time, reset on the dot" model (as used by e.g.
+    /// ClickHouse-style interval quotas): up to `max_burst` cells are admitted at any point
+    /// within a window, and the count is reset to zero as soon as the window ends, instead of
+    /// trickling back in over time. The window length is
+    /// [`burst_size_replenished_in`](#method.burst_size_replenished_in), which reports `period`
+    /// rounded down to a whole number of microseconds per cell (exactly `period` when it divides
+    /// evenly across `max_burst` cells).
+    ///
+    /// Returns `None` if `period` divided evenly across `max_burst` cells would round down to
+    /// a zero-length replenish interval.
+    pub fn fixed_window(max_burst: NonZeroU32, period: Duration) -> Option<Quota> {
+        let replenish_interval_micros = period.as_micros() / max_burst.get() as u128;
+        if replenish_interval_micros == 0 {
+            return None;
+        }
+        Some(Quota {
+            max_burst,
+            replenish_1_per: Duration::from_micros(replenish_interval_micros as u64),
+            burst_window: None,
+            strict_sliding_window: false,
+            fixed_window: true,
+        })
+    }
 }
 
 /// Retrieving information about a quota
@@ -115,6 +215,22 @@ impl Quota {
         let fill_in_micros = self.replenish_1_per.as_micros() * self.max_burst.get() as u128;
         Duration::from_micros(fill_in_micros as u64)
     }
+
+    /// The `(burst_duration, burst_limit)` set by [`with_burst_window`](#method.with_burst_window),
+    /// if any.
+    pub const fn burst_window(&self) -> Option<(Duration, NonZeroU32)> {
+        self.burst_window
+    }
+
+    /// Whether [`enforce_sliding_window`](#method.enforce_sliding_window) was set.
+    pub const fn is_sliding_window(&self) -> bool {
+        self.strict_sliding_window
+    }
+
+    /// Whether this quota was built with [`fixed_window`](#method.fixed_window).
+    pub const fn is_fixed_window(&self) -> bool {
+        self.fixed_window
+    }
 }
 
 #[cfg(test)]
@@ -137,4 +253,51 @@ mod test {
             secondly.replenish_interval()
         );
     }
+
+    #[test]
+    fn with_burst_window_is_off_by_default() {
+        let quota = Quota::per_second(nonzero!(10u32));
+        assert_eq!(quota.burst_window(), None);
+
+        let quota = quota.with_burst_window(Duration::from_millis(100), nonzero!(3u32));
+        assert_eq!(
+            quota.burst_window(),
+            Some((Duration::from_millis(100), nonzero!(3u32)))
+        );
+    }
+
+    #[test]
+    fn enforce_sliding_window_is_off_by_default() {
+        let quota = Quota::per_second(nonzero!(10u32));
+        assert!(!quota.is_sliding_window());
+        assert!(quota.enforce_sliding_window().is_sliding_window());
+    }
+
+    #[test]
+    fn fixed_window_reports_the_period_as_its_replenish_window() {
+        let quota = Quota::fixed_window(nonzero!(10u32), Duration::from_secs(1)).unwrap();
+        assert!(quota.is_fixed_window());
+        assert_eq!(quota.burst_size_replenished_in(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn fixed_window_rejects_a_period_too_short_to_divide() {
+        assert_eq!(
+            Quota::fixed_window(nonzero!(10u32), Duration::from_micros(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn new_expresses_a_rate_directly() {
+        let quota = Quota::new(nonzero!(4u32), Duration::from_secs(2)).unwrap();
+        assert_eq!(quota.burst_size(), nonzero!(4u32));
+        assert_eq!(quota.replenish_interval(), Duration::from_millis(500));
+        assert_eq!(quota.burst_size_replenished_in(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn new_rejects_a_period_too_short_to_divide() {
+        assert_eq!(Quota::new(nonzero!(10u32), Duration::from_micros(1)), None);
+    }
 }
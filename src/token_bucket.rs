@@ -1,53 +1,352 @@
-use std::time::Instant;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use log::debug;
 
 use crate::quota::Quota;
 
-pub type NotUntil = Instant;
+/// Why a check was rejected: the quota that rejected it, and the earliest instant a
+/// retry would conform.
+///
+/// This mirrors governor's `NotUntil` and exists so callers can build a `Retry-After`
+/// header or a sleep-and-retry loop without re-deriving timing from `replenish_interval()`
+/// by hand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotUntil {
+    quota: Quota,
+    earliest_possible: Instant,
+}
+
+impl NotUntil {
+    fn new(quota: Quota, earliest_possible: Instant) -> Self {
+        Self {
+            quota,
+            earliest_possible,
+        }
+    }
+
+    /// The quota that rejected the request.
+    pub const fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// The absolute instant at which the request would first conform.
+    pub const fn earliest_possible(&self) -> Instant {
+        self.earliest_possible
+    }
+
+    /// How long to wait, starting from `now`, until the request would conform.
+    /// Zero if `now` is already at or past [`earliest_possible`](#method.earliest_possible).
+    pub fn wait_time_from(&self, now: Instant) -> Duration {
+        self.earliest_possible
+            .checked_duration_since(now)
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// A source of the current time, injected into [`TokenBucket`] and its
+/// siblings so tests can control the passage of time without sleeping.
+pub trait Clock: Clone {
+    fn now(&self) -> Instant;
+}
+
+/// The default [`Clock`], backed by [`Instant::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [`Clock`] that only moves forward when told to, for deterministic tests.
+#[derive(Debug, Clone)]
+pub struct FakeClock(Arc<Mutex<Instant>>);
+
+impl FakeClock {
+    pub fn new(start: Instant) -> Self {
+        Self(Arc::new(Mutex::new(start)))
+    }
+
+    /// Moves this clock (and every handle cloned from it) forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut instant = self.0.lock().unwrap();
+        *instant += duration;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// The sliding-window-counter approximation backing [`Quota::enforce_sliding_window`]:
+/// two fixed-window counters (the current window and the one before it) instead of a
+/// full timestamp log.
+#[derive(Debug, Clone, Copy)]
+struct SlidingWindow {
+    window_start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+impl SlidingWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    /// Rolls the window forward past `now` if it has elapsed, dropping any window
+    /// that wasn't the immediately-preceding one.
+    fn roll(&mut self, now: Instant, window: Duration) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < window {
+            return;
+        }
+        let windows_passed = (elapsed.as_nanos() / window.as_nanos().max(1)) as u32;
+        self.previous = if windows_passed == 1 { self.current } else { 0 };
+        self.current = 0;
+        self.window_start += window * windows_passed;
+    }
+
+    /// The approximate count of cells in the trailing window of `window` ending `now`.
+    fn estimate(&self, now: Instant, window: Duration) -> f64 {
+        let elapsed = now.duration_since(self.window_start).as_secs_f64();
+        let window_secs = window.as_secs_f64();
+        let decay = (1.0 - elapsed / window_secs).clamp(0.0, 1.0);
+        self.current as f64 + self.previous as f64 * decay
+    }
+}
+
+/// The hard-reset window backing [`Quota::fixed_window`]: the full `max_burst` allowance
+/// is available again the instant `window_end` passes, rather than trickling back in.
+#[derive(Debug, Clone, Copy)]
+struct FixedWindow {
+    window_end: Instant,
+    used: u32,
+}
+
+impl FixedWindow {
+    fn new(now: Instant, period: Duration) -> Self {
+        Self {
+            window_end: now + period,
+            used: 0,
+        }
+    }
+
+    /// Resets `used` to zero and advances `window_end` in whole `period` steps past `now`,
+    /// if the window has elapsed.
+    fn roll(&mut self, now: Instant, period: Duration) {
+        if now < self.window_end {
+            return;
+        }
+        let elapsed_past_end = now.duration_since(self.window_end);
+        let periods_passed = (elapsed_past_end.as_nanos() / period.as_nanos().max(1)) as u32 + 1;
+        self.window_end += period * periods_passed;
+        self.used = 0;
+    }
+}
 
 #[derive(Debug, Clone)]
-pub struct TokenBucket {
+pub struct TokenBucket<C: Clock = SystemClock> {
     quota: Quota,
     tokens: u32,
     last_update: Instant,
+    clock: C,
+    /// Grants made within the trailing `burst_window` duration, oldest first.
+    /// Empty and unused when `quota.burst_window()` is `None`.
+    recent_grants: VecDeque<(Instant, u32)>,
+    /// Used instead of `tokens`/`last_update` when `quota.is_sliding_window()`.
+    sliding_window: Option<SlidingWindow>,
+    /// Used instead of `tokens`/`last_update` when `quota.is_fixed_window()`.
+    fixed_window: Option<FixedWindow>,
 }
 
-impl TokenBucket {
+impl TokenBucket<SystemClock> {
     pub fn new(quota: Quota) -> Self {
+        Self::with_clock(quota, SystemClock)
+    }
+}
+
+impl<C: Clock> TokenBucket<C> {
+    pub fn with_clock(quota: Quota, clock: C) -> Self {
+        let now = clock.now();
         Self {
             quota,
-            tokens: 0,
-            last_update: Instant::now(),
+            tokens: if quota.is_sliding_window() {
+                0
+            } else {
+                quota.max_burst.get()
+            },
+            last_update: now,
+            clock,
+            recent_grants: VecDeque::new(),
+            sliding_window: quota.is_sliding_window().then(|| SlidingWindow::new(now)),
+            fixed_window: quota
+                .is_fixed_window()
+                .then(|| FixedWindow::new(now, quota.burst_size_replenished_in())),
         }
     }
+
     pub fn check_n(&self, n: u32) -> Result<(), NotUntil> {
+        let now = self.clock.now();
+        let steady = if let Some(sliding_window) = &self.sliding_window {
+            self.check_sliding_window(sliding_window, n, now)
+        } else if let Some(fixed_window) = &self.fixed_window {
+            self.check_fixed_window(fixed_window, n, now)
+        } else {
+            self.check_steady_rate(n, now)
+        };
+        steady.and_then(|_| self.check_burst_window(n, now))
+    }
+
+    fn check_fixed_window(
+        &self,
+        fixed_window: &FixedWindow,
+        n: u32,
+        now: Instant,
+    ) -> Result<(), NotUntil> {
+        let mut fixed_window = *fixed_window;
+        fixed_window.roll(now, self.quota.burst_size_replenished_in());
+
+        if fixed_window.used + n <= self.quota.max_burst.get() {
+            Ok(())
+        } else {
+            Err(NotUntil::new(self.quota, fixed_window.window_end))
+        }
+    }
+
+    fn check_sliding_window(
+        &self,
+        sliding_window: &SlidingWindow,
+        n: u32,
+        now: Instant,
+    ) -> Result<(), NotUntil> {
+        let window = self.quota.burst_size_replenished_in();
+        let mut sliding_window = *sliding_window;
+        sliding_window.roll(now, window);
+
+        let estimate = sliding_window.estimate(now, window);
+        let limit = self.quota.max_burst.get() as f64;
+        if estimate + n as f64 <= limit {
+            return Ok(());
+        }
+
+        let overflow = estimate + n as f64 - limit;
+        let wait = if sliding_window.previous > 0 {
+            Duration::from_secs_f64(
+                (overflow / sliding_window.previous as f64) * window.as_secs_f64(),
+            )
+        } else {
+            window.saturating_sub(now.duration_since(sliding_window.window_start))
+        };
+        Err(NotUntil::new(self.quota, now + wait))
+    }
+
+    fn check_steady_rate(&self, n: u32, now: Instant) -> Result<(), NotUntil> {
         if n <= self.tokens {
             return Ok(());
         }
 
         let need_tokens = n - self.tokens;
         let need_dur = self.quota.replenish_1_per * need_tokens;
-        if self.last_update.elapsed() < need_dur {
-            return Err(self.last_update + need_dur);
+        let elapsed = now.duration_since(self.last_update);
+        if elapsed < need_dur {
+            return Err(NotUntil::new(self.quota, self.last_update + need_dur));
+        }
+        Ok(())
+    }
+
+    fn check_burst_window(&self, n: u32, now: Instant) -> Result<(), NotUntil> {
+        let Some((burst_duration, burst_limit)) = self.quota.burst_window() else {
+            return Ok(());
+        };
+        let window_start = now.checked_sub(burst_duration).unwrap_or(now);
+
+        let mut granted_in_window = 0u32;
+        let mut oldest_in_window = None;
+        for &(grant_at, count) in &self.recent_grants {
+            if grant_at > window_start {
+                granted_in_window += count;
+                oldest_in_window.get_or_insert(grant_at);
+            }
+        }
+
+        if granted_in_window + n > burst_limit.get() {
+            let oldest = oldest_in_window.unwrap_or(now);
+            return Err(NotUntil::new(self.quota, oldest + burst_duration));
         }
         Ok(())
     }
 
+    /// The number of tokens currently held, as of the last [`try_take_n`](#method.try_take_n).
+    /// Always `0` when [`Quota::enforce_sliding_window`] is in effect, since that mode
+    /// tracks counters rather than a token balance.
+    pub fn tokens(&self) -> u32 {
+        self.tokens
+    }
+
+    /// Reverses a debit of `n` tokens made by a prior successful [`try_take_n`](#method.try_take_n),
+    /// for callers that need all-or-nothing semantics across several buckets without
+    /// cloning them up front: apply each bucket's debit in place, and refund the ones
+    /// already charged if a later bucket in the batch rejects.
+    pub(crate) fn refund(&mut self, n: u32) {
+        if let Some(sliding_window) = self.sliding_window.as_mut() {
+            sliding_window.current = sliding_window.current.saturating_sub(n);
+        } else if let Some(fixed_window) = self.fixed_window.as_mut() {
+            fixed_window.used = fixed_window.used.saturating_sub(n);
+        } else {
+            self.tokens = std::cmp::min(self.tokens + n, self.quota.max_burst.get());
+        }
+        if self.quota.burst_window().is_some() {
+            if matches!(self.recent_grants.back(), Some(&(_, last_n)) if last_n == n) {
+                self.recent_grants.pop_back();
+            }
+        }
+    }
+
     pub fn try_take_n(&mut self, n: u32) -> Result<(), NotUntil> {
-        let earned_tokens = (self.last_update.elapsed().as_micros()
-            / self.quota.replenish_1_per.as_micros()) as u32;
-        debug!(
-            "earned_tokens: {} for quota: {:?} in duration: {:?}",
-            earned_tokens,
-            self.quota,
-            self.last_update.elapsed()
-        );
-        self.tokens = std::cmp::min(self.tokens + earned_tokens, self.quota.max_burst.get());
-        debug!("tokens: {}", self.tokens);
-        self.last_update = Instant::now();
+        let now = self.clock.now();
+
+        if let Some(sliding_window) = self.sliding_window.as_mut() {
+            sliding_window.roll(now, self.quota.burst_size_replenished_in());
+        } else if let Some(fixed_window) = self.fixed_window.as_mut() {
+            fixed_window.roll(now, self.quota.burst_size_replenished_in());
+        } else {
+            let elapsed = now.duration_since(self.last_update);
+            let earned_tokens =
+                (elapsed.as_micros() / self.quota.replenish_1_per.as_micros()) as u32;
+            debug!(
+                "earned_tokens: {} for quota: {:?} in duration: {:?}",
+                earned_tokens, self.quota, elapsed
+            );
+            self.tokens = std::cmp::min(self.tokens + earned_tokens, self.quota.max_burst.get());
+            debug!("tokens: {}", self.tokens);
+            self.last_update = now;
+        }
+
+        if let Some((burst_duration, _)) = self.quota.burst_window() {
+            let window_start = now.checked_sub(burst_duration).unwrap_or(now);
+            self.recent_grants.retain(|&(grant_at, _)| grant_at > window_start);
+        }
+
         self.check_n(n).and_then(|_| {
-            self.tokens -= n;
+            if let Some(sliding_window) = self.sliding_window.as_mut() {
+                sliding_window.current += n;
+            } else if let Some(fixed_window) = self.fixed_window.as_mut() {
+                fixed_window.used += n;
+            } else {
+                self.tokens -= n;
+            }
+            if n > 0 && self.quota.burst_window().is_some() {
+                self.recent_grants.push_back((now, n));
+            }
             Ok(())
         })
     }
@@ -90,4 +389,100 @@ mod test {
         assert_eq!(bucket.tokens, 0);
         assert!(bucket.check_n(1).is_err());
     }
+
+    #[test]
+    fn fake_clock_replenishes_without_sleeping() {
+        let clock = FakeClock::new(Instant::now());
+        let mut bucket =
+            TokenBucket::with_clock(Quota::per_second(nonzero!(4u32)), clock.clone());
+        assert_eq!(bucket.try_take_n(4), Ok(()));
+        assert!(bucket.try_take_n(1).is_err());
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(bucket.check_n(2), Ok(()));
+        assert!(bucket.check_n(3).is_err());
+        assert_eq!(bucket.try_take_n(2), Ok(()));
+
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(bucket.try_take_n(1), Ok(()));
+    }
+
+    #[test]
+    fn burst_window_bounds_spikes_independent_of_steady_rate() {
+        let clock = FakeClock::new(Instant::now());
+        let quota = Quota::per_second(nonzero!(100u32))
+            .with_burst_window(Duration::from_millis(100), nonzero!(3u32));
+        let mut bucket = TokenBucket::with_clock(quota, clock.clone());
+
+        // the steady rate alone would let the full burst of 100 cells through at once,
+        // but the burst window caps any 100ms slice at 3.
+        assert_eq!(bucket.try_take_n(3), Ok(()));
+        assert!(bucket.try_take_n(1).is_err());
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(bucket.try_take_n(1), Ok(()));
+    }
+
+    #[test]
+    fn sliding_window_never_admits_more_than_burst_size_in_one_window() {
+        let clock = FakeClock::new(Instant::now());
+        let quota = Quota::per_minute(nonzero!(10u32)).enforce_sliding_window();
+        let mut bucket = TokenBucket::with_clock(quota, clock.clone());
+
+        // the lenient model would let 10 more through right away once the minute
+        // timer elapses; the strict sliding window must not.
+        assert_eq!(bucket.try_take_n(10), Ok(()));
+        clock.advance(Duration::from_secs(61));
+        assert!(bucket.try_take_n(10).is_err());
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(bucket.try_take_n(10), Ok(()));
+    }
+
+    #[test]
+    fn fixed_window_resets_the_full_allowance_at_the_boundary_instead_of_trickling() {
+        let clock = FakeClock::new(Instant::now());
+        let quota = Quota::fixed_window(nonzero!(10u32), Duration::from_secs(60)).unwrap();
+        let mut bucket = TokenBucket::with_clock(quota, clock.clone());
+
+        assert_eq!(bucket.try_take_n(10), Ok(()));
+        clock.advance(Duration::from_secs(59));
+        // unlike the steady-rate or sliding-window models, nothing has trickled back yet.
+        assert!(bucket.try_take_n(1).is_err());
+
+        clock.advance(Duration::from_secs(1));
+        // the window just ended: the full burst is available again, all at once.
+        assert_eq!(bucket.try_take_n(10), Ok(()));
+    }
+
+    #[test]
+    fn fake_clock_reports_exact_not_until() {
+        let start = Instant::now();
+        let clock = FakeClock::new(start);
+        let mut bucket =
+            TokenBucket::with_clock(Quota::per_second(nonzero!(4u32)), clock.clone());
+        assert_eq!(bucket.try_take_n(4), Ok(()));
+        let not_until = bucket.check_n(1).unwrap_err();
+        assert_eq!(
+            not_until.earliest_possible(),
+            start + Duration::from_millis(250)
+        );
+        assert_eq!(
+            not_until.wait_time_from(start),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            not_until.wait_time_from(start + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn not_until_carries_the_quota_that_rejected_it() {
+        let quota = Quota::per_second(nonzero!(4u32));
+        let mut bucket = TokenBucket::new(quota);
+        assert_eq!(bucket.try_take_n(4), Ok(()));
+        let not_until = bucket.check_n(1).unwrap_err();
+        assert_eq!(not_until.quota(), quota);
+    }
 }
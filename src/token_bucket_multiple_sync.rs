@@ -1,24 +1,28 @@
 use crate::{
     quota::Quota,
-    token_bucket::{NotUntil, TokenBucket},
+    token_bucket::{Clock, NotUntil, SystemClock, TokenBucket},
 };
 
 #[derive(Debug, Clone)]
-pub struct TokenBucketMultipleSync<const N: usize> {
-    buckets: [TokenBucket; N],
+pub struct TokenBucketMultipleSync<const N: usize, C: Clock = SystemClock> {
+    buckets: [TokenBucket<C>; N],
 }
 
-impl<const N: usize> TokenBucketMultipleSync<N> {
+impl<const N: usize> TokenBucketMultipleSync<N, SystemClock> {
     pub fn new(quotas: [Quota; N]) -> Self {
+        Self::with_clock(quotas, SystemClock)
+    }
+}
+
+impl<const N: usize, C: Clock> TokenBucketMultipleSync<N, C> {
+    pub fn with_clock(quotas: [Quota; N], clock: C) -> Self {
         if N <= 1 {
             panic!("TokenBucketMultipleSync needs at least two buckets");
         }
-        let buckets = quotas.map(TokenBucket::new);
+        let buckets = quotas.map(|quota| TokenBucket::with_clock(quota, clock.clone()));
         Self { buckets }
     }
-}
 
-impl<const N: usize> TokenBucketMultipleSync<N> {
     pub fn check_ns(&self, ns: [u32; N]) -> Result<(), NotUntil> {
         // reduce the self.buckets.iter() with result::and
         self.buckets
@@ -30,22 +34,27 @@ impl<const N: usize> TokenBucketMultipleSync<N> {
     }
 
     pub fn try_take_ns(&mut self, ns: [u32; N]) -> Result<(), NotUntil> {
-        let mut buckets_new = self.buckets.clone();
-        let res = buckets_new
-            .iter_mut()
-            .zip(ns)
-            .try_for_each(|(bucket, n)| bucket.try_take_n(n));
-
-        res.and_then(|_| {
-            self.buckets = buckets_new;
-            Ok(())
-        })
+        let mut charged = Vec::with_capacity(N);
+        for (i, (bucket, n)) in self.buckets.iter_mut().zip(ns).enumerate() {
+            match bucket.try_take_n(n) {
+                Ok(()) => charged.push(i),
+                Err(not_until) => {
+                    for i in charged {
+                        self.buckets[i].refund(ns[i]);
+                    }
+                    return Err(not_until);
+                }
+            }
+        }
+        Ok(())
     }
 }
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::token_bucket::FakeClock;
     use nonzero_ext::nonzero;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_new() {
@@ -120,4 +129,20 @@ mod test {
         assert_eq!(bucket.try_take_ns([1, 1]), Ok(()));
         assert!(bucket.try_take_ns([1, 0]).is_err());
     }
+
+    #[test]
+    fn test_fake_clock_deterministic_replenish() {
+        let clock = FakeClock::new(Instant::now());
+        let mut bucket = TokenBucketMultipleSync::with_clock(
+            [
+                Quota::per_second(nonzero!(4u32)),
+                Quota::per_second(nonzero!(2u32)),
+            ],
+            clock.clone(),
+        );
+        assert_eq!(bucket.try_take_ns([4, 2]), Ok(()));
+        assert!(bucket.try_take_ns([1, 1]).is_err());
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(bucket.try_take_ns([2, 1]), Ok(()));
+    }
 }
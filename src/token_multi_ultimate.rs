@@ -1,36 +1,42 @@
-use std::time::Duration;
-use std::{collections::HashMap, time::Instant};
-
-use rand::distributions::{Distribution, Uniform};
-use rand::thread_rng;
-
-use futures_timer::Delay;
+use std::collections::HashMap;
 
 use crate::{
     quota::Quota,
-    token_bucket::{NotUntil, TokenBucket},
+    token_bucket::{Clock, NotUntil, SystemClock, TokenBucket},
+    waiter_queue::WaiterQueue,
 };
 
-use once_cell::sync::Lazy;
-
-static JITTER_DIST: Lazy<Uniform<u64>> = Lazy::new(|| Uniform::new(0, 10));
-
-pub struct TokenBucketUltimate(HashMap<String, TokenBucket>);
+pub struct TokenBucketUltimate<C: Clock = SystemClock> {
+    buckets: HashMap<String, TokenBucket<C>>,
+    clock: C,
+    waiters: WaiterQueue,
+}
 
-impl TokenBucketUltimate {
+impl TokenBucketUltimate<SystemClock> {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> TokenBucketUltimate<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            clock,
+            waiters: WaiterQueue::new(),
+        }
     }
 
     pub fn insert(&mut self, key: String, quota: Quota) {
-        self.0.insert(key, TokenBucket::new(quota));
+        self.buckets
+            .insert(key, TokenBucket::with_clock(quota, self.clock.clone()));
     }
 
     pub fn check_n(&self, pairs: &[(&str, u32)]) -> Result<(), NotUntil> {
         pairs
             .iter()
             .map(|&(key, n)| {
-                self.0
+                self.buckets
                     .get(key)
                     .expect("Do not use a key that is not inserted")
                     .check_n(n)
@@ -39,31 +45,30 @@ impl TokenBucketUltimate {
     }
 
     pub fn try_take_n(&mut self, pairs: &[(&str, u32)]) -> Result<(), NotUntil> {
-        let mut buckets_new = self.0.clone();
-        let res = pairs
-            .iter()
-            .try_for_each(|&(key, n)| buckets_new.get_mut(key).unwrap().try_take_n(n));
-
-        res.and_then(|_| {
-            self.0 = buckets_new;
-            Ok(())
-        })
+        let mut charged = Vec::with_capacity(pairs.len());
+        for &(key, n) in pairs {
+            let bucket = self
+                .buckets
+                .get_mut(key)
+                .expect("Do not use a key that is not inserted");
+            match bucket.try_take_n(n) {
+                Ok(()) => charged.push((key, n)),
+                Err(not_until) => {
+                    for (key, n) in charged {
+                        self.buckets.get_mut(key).unwrap().refund(n);
+                    }
+                    return Err(not_until);
+                }
+            }
+        }
+        Ok(())
     }
 
     pub async fn take_n(&mut self, pairs: &[(&str, u32)]) {
         loop {
-            println!("Looping...");
             match self.try_take_n(pairs) {
                 Ok(_) => return,
-                Err(not_until) => {
-                    let jitter = JITTER_DIST.sample(&mut thread_rng());
-                    let jitter_dur = Duration::from_micros(jitter);
-                    println!("not_until: {:?}, now: {:?}", not_until, Instant::now());
-                    let delay = Delay::new(not_until.duration_since(Instant::now()) + jitter_dur);
-                    println!("delay: {:?}", delay);
-                    delay.await;
-                    println!("delay done");
-                }
+                Err(not_until) => self.waiters.wait_turn(not_until.earliest_possible()).await,
             }
         }
     }
@@ -72,6 +77,7 @@ impl TokenBucketUltimate {
 #[cfg(test)]
 mod tests {
     use nonzero_ext::nonzero;
+    use std::time::Instant;
 
     use super::*;
 
@@ -156,4 +162,20 @@ mod tests {
         assert!(start.elapsed() > Duration::from_secs(2));
         assert!(start.elapsed() < Duration::from_secs_f32(2.5));
     }
+
+    #[tokio::test]
+    async fn test_fake_clock_deterministic() {
+        use crate::token_bucket::FakeClock;
+
+        let clock = FakeClock::new(Instant::now());
+        let mut ultimate = TokenBucketUltimate::with_clock(clock.clone());
+        ultimate.insert("a".to_string(), Quota::per_second(nonzero!(10u32)));
+
+        assert_eq!(ultimate.try_take_n(&[("a", 10)]), Ok(()));
+        assert!(ultimate.try_take_n(&[("a", 1)]).is_err());
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(ultimate.check_n(&[("a", 5)]), Ok(()));
+        assert!(ultimate.check_n(&[("a", 6)]).is_err());
+    }
 }
@@ -0,0 +1,144 @@
+use linked_hash_map::LinkedHashMap;
+
+use crate::{
+    quota::Quota,
+    token_bucket::{Clock, NotUntil, SystemClock, TokenBucket},
+    waiter_queue::WaiterQueue,
+};
+
+/// A `String`-keyed rate limiter with a bounded keyspace, for dynamic keys such as
+/// per-IP or per-user limiting where [`TokenBucketUltimate`](crate::token_multi_ultimate::TokenBucketUltimate)'s
+/// unbounded `HashMap` and panic-on-unknown-key behavior don't work.
+///
+/// Unknown keys are created lazily on first access using `default_quota`. Once
+/// `capacity` distinct keys are tracked, the least-recently-used one is evicted to
+/// make room, so memory stays bounded no matter how many distinct keys are seen over
+/// the limiter's lifetime.
+pub struct BoundedTokenBucketUltimate<C: Clock = SystemClock> {
+    buckets: LinkedHashMap<String, TokenBucket<C>>,
+    capacity: usize,
+    default_quota: Quota,
+    clock: C,
+    waiters: WaiterQueue,
+}
+
+impl BoundedTokenBucketUltimate<SystemClock> {
+    pub fn new(capacity: usize, default_quota: Quota) -> Self {
+        Self::with_clock(capacity, default_quota, SystemClock)
+    }
+}
+
+impl<C: Clock> BoundedTokenBucketUltimate<C> {
+    pub fn with_clock(capacity: usize, default_quota: Quota, clock: C) -> Self {
+        assert!(
+            capacity > 0,
+            "BoundedTokenBucketUltimate needs a capacity of at least one"
+        );
+        Self {
+            buckets: LinkedHashMap::new(),
+            capacity,
+            default_quota,
+            clock,
+            waiters: WaiterQueue::new(),
+        }
+    }
+
+    /// The number of distinct keys currently tracked.
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// The total number of tokens currently held across every tracked key.
+    pub fn total_weight(&self) -> u32 {
+        self.buckets.values().map(TokenBucket::tokens).sum()
+    }
+
+    /// Moves `key` to the most-recently-used end, lazily creating it (evicting the
+    /// least-recently-used key if at `capacity`) if it isn't tracked yet.
+    fn touch(&mut self, key: &str) {
+        if self.buckets.get_refresh(key).is_some() {
+            return;
+        }
+        if self.buckets.len() >= self.capacity {
+            self.buckets.pop_front();
+        }
+        self.buckets.insert(
+            key.to_string(),
+            TokenBucket::with_clock(self.default_quota, self.clock.clone()),
+        );
+    }
+
+    pub fn check_n(&mut self, key: &str, n: u32) -> Result<(), NotUntil> {
+        self.touch(key);
+        self.buckets
+            .get(key)
+            .expect("touch just inserted this key")
+            .check_n(n)
+    }
+
+    pub fn try_take_n(&mut self, key: &str, n: u32) -> Result<(), NotUntil> {
+        self.touch(key);
+        self.buckets
+            .get_mut(key)
+            .expect("touch just inserted this key")
+            .try_take_n(n)
+    }
+
+    pub async fn take_n(&mut self, key: &str, n: u32) {
+        loop {
+            match self.try_take_n(key, n) {
+                Ok(_) => return,
+                Err(not_until) => self.waiters.wait_turn(not_until.earliest_possible()).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    fn ultimate(capacity: usize) -> BoundedTokenBucketUltimate {
+        BoundedTokenBucketUltimate::new(capacity, Quota::per_second(nonzero!(10u32)))
+    }
+
+    #[test]
+    fn unknown_keys_are_created_lazily() {
+        let mut ultimate = ultimate(2);
+        assert_eq!(ultimate.len(), 0);
+        assert_eq!(ultimate.try_take_n("a", 5), Ok(()));
+        assert_eq!(ultimate.len(), 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_key_past_capacity() {
+        let mut ultimate = ultimate(2);
+        assert_eq!(ultimate.try_take_n("a", 1), Ok(()));
+        assert_eq!(ultimate.try_take_n("b", 1), Ok(()));
+        // touching "a" again makes "b" the least-recently-used
+        assert_eq!(ultimate.try_take_n("a", 1), Ok(()));
+        assert_eq!(ultimate.try_take_n("c", 1), Ok(()));
+        assert_eq!(ultimate.len(), 2);
+
+        // "b" was evicted, so it comes back with a fresh, full bucket
+        assert_eq!(ultimate.try_take_n("b", 10), Ok(()));
+        // "a" is now the least-recently-used (it hasn't been touched since
+        // the first round), so re-inserting "b" evicted "a" too -- it also
+        // comes back fresh
+        assert_eq!(ultimate.try_take_n("a", 10), Ok(()));
+    }
+
+    #[test]
+    fn total_weight_reflects_remaining_tokens() {
+        let mut ultimate = ultimate(2);
+        // each key starts with a fresh, full 10-token bucket.
+        assert_eq!(ultimate.try_take_n("a", 4), Ok(()));
+        assert_eq!(ultimate.try_take_n("b", 1), Ok(()));
+        assert_eq!(ultimate.total_weight(), (10 - 4) + (10 - 1));
+    }
+}
@@ -1,40 +1,47 @@
 use crate::{
     quota::Quota,
-    token_bucket::{NotUntil, TokenBucket},
+    token_bucket::{Clock, NotUntil, SystemClock, TokenBucket},
+    waiter_queue::WaiterQueue,
 };
-use futures_timer::Delay;
-use log::debug;
-use once_cell::sync::Lazy;
-use rand::distributions::{Distribution, Uniform};
-use rand::thread_rng;
 use std::hash::Hash;
-use std::time::Duration;
 use std::{collections::HashMap, time::Instant};
 use strum::IntoEnumIterator;
 
-static JITTER_DIST: Lazy<Uniform<u64>> = Lazy::new(|| Uniform::new(0, 10));
-
 pub trait QuotasTrait {
     fn get_quota(&self) -> Quota;
 }
 
-pub struct TokenBucketUltimate<T: QuotasTrait + IntoEnumIterator>(HashMap<T, TokenBucket>);
+pub struct TokenBucketUltimate<T: QuotasTrait + IntoEnumIterator, C: Clock = SystemClock> {
+    buckets: HashMap<T, TokenBucket<C>>,
+    clock: C,
+    waiters: WaiterQueue,
+}
 
-impl<T: QuotasTrait + IntoEnumIterator + Hash + Eq + Clone> TokenBucketUltimate<T> {
+impl<T: QuotasTrait + IntoEnumIterator + Hash + Eq + Clone> TokenBucketUltimate<T, SystemClock> {
     pub fn new() -> Self {
-        let mut map = HashMap::new();
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<T: QuotasTrait + IntoEnumIterator + Hash + Eq + Clone, C: Clock> TokenBucketUltimate<T, C> {
+    pub fn with_clock(clock: C) -> Self {
+        let mut buckets = HashMap::new();
         for variant in T::iter() {
             let quota = variant.get_quota();
-            map.insert(variant, TokenBucket::new(quota));
+            buckets.insert(variant, TokenBucket::with_clock(quota, clock.clone()));
+        }
+        Self {
+            buckets,
+            clock,
+            waiters: WaiterQueue::new(),
         }
-        Self(map)
     }
 
     pub fn check_n(&self, pairs: &[(T, u32)]) -> Result<(), NotUntil> {
         pairs
             .iter()
             .map(|(key, n)| {
-                self.0
+                self.buckets
                     .get(key)
                     .expect("Do not use a key that is not inserted")
                     .check_n(*n)
@@ -43,28 +50,30 @@ impl<T: QuotasTrait + IntoEnumIterator + Hash + Eq + Clone> TokenBucketUltimate<
     }
 
     pub fn try_take_n(&mut self, pairs: &[(T, u32)]) -> Result<(), NotUntil> {
-        let mut buckets_new = self.0.clone();
-        let res = pairs
-            .iter()
-            .try_for_each(|(key, n)| buckets_new.get_mut(key).unwrap().try_take_n(*n));
-
-        res.and_then(|_| {
-            self.0 = buckets_new;
-            Ok(())
-        })
+        let mut charged = Vec::with_capacity(pairs.len());
+        for (key, n) in pairs {
+            let bucket = self
+                .buckets
+                .get_mut(key)
+                .expect("Do not use a key that is not inserted");
+            match bucket.try_take_n(*n) {
+                Ok(()) => charged.push((key.clone(), *n)),
+                Err(not_until) => {
+                    for (key, n) in charged {
+                        self.buckets.get_mut(&key).unwrap().refund(n);
+                    }
+                    return Err(not_until);
+                }
+            }
+        }
+        Ok(())
     }
 
     pub async fn take_n(&mut self, pairs: &[(T, u32)]) {
         loop {
             match self.try_take_n(pairs) {
                 Ok(_) => return,
-                Err(not_until) => {
-                    let jitter = JITTER_DIST.sample(&mut thread_rng());
-                    let jitter_dur = Duration::from_millis(jitter);
-                    let delay_dur = not_until.duration_since(Instant::now()) + jitter_dur;
-                    Delay::new(delay_dur).await;
-                    debug!("delay_dur: {:?}", delay_dur);
-                }
+                Err(not_until) => self.waiters.wait_turn(not_until.earliest_possible()).await,
             }
         }
     }
@@ -80,6 +89,7 @@ mod tests {
     use tokio::time::Duration;
 
     use test_log::test;
+    use strum_macros::EnumIter;
 
     use super::*;
 
@@ -187,4 +197,20 @@ mod tests {
         assert!(start.elapsed() > Duration::from_secs(2));
         assert!(start.elapsed() < Duration::from_secs_f32(2.5));
     }
+
+    #[tokio::test]
+    async fn test_fake_clock_deterministic() {
+        use crate::token_bucket::FakeClock;
+
+        let clock = FakeClock::new(Instant::now());
+        let mut ultimate: TokenBucketUltimate<Quotas, FakeClock> =
+            TokenBucketUltimate::with_clock(clock.clone());
+
+        assert_eq!(ultimate.try_take_n(&[(Quotas::Ten, 10)]), Ok(()));
+        assert!(ultimate.try_take_n(&[(Quotas::Ten, 1)]).is_err());
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(ultimate.check_n(&[(Quotas::Ten, 5)]), Ok(()));
+        assert!(ultimate.check_n(&[(Quotas::Ten, 6)]).is_err());
+    }
 }
@@ -0,0 +1,156 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use futures_timer::Delay;
+use tokio::sync::Notify;
+
+/// A deadline-ordered wakeup scheduler for `take_n` retries.
+///
+/// Polling `try_take_n` in a bare loop means every blocked caller independently
+/// sleeps for its own `NotUntil` and races the others on wakeup: a thundering herd
+/// with no ordering guarantee among waiters. This queues each blocked caller's
+/// deadline instead, and only lets the earliest-eligible one (ties broken by arrival
+/// order) through on each wakeup, so sustained contention can't starve a waiter that
+/// has been in line the longest.
+#[derive(Default)]
+pub struct WaiterQueue {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    heap: BinaryHeap<Reverse<Ticket>>,
+    next_seq: u64,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Ticket {
+    wake_at: Instant,
+    seq: u64,
+}
+
+impl Ord for Ticket {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.wake_at
+            .cmp(&other.wake_at)
+            .then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for Ticket {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl WaiterQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `wake_at` as this caller's deadline and waits until it is both past
+    /// and this caller's turn: every other registered deadline that is earlier, or
+    /// equal but registered earlier, must have already taken its turn.
+    pub async fn wait_turn(&self, wake_at: Instant) {
+        let ticket = {
+            let mut state = self.state.lock().unwrap();
+            let ticket = Ticket {
+                wake_at,
+                seq: state.next_seq,
+            };
+            state.next_seq += 1;
+            state.heap.push(Reverse(ticket));
+            ticket
+        };
+
+        loop {
+            let (is_front, front_deadline) = {
+                let state = self.state.lock().unwrap();
+                match state.heap.peek() {
+                    Some(Reverse(front)) => (*front == ticket, front.wake_at),
+                    None => (true, ticket.wake_at),
+                }
+            };
+
+            if is_front && Instant::now() >= front_deadline {
+                break;
+            }
+
+            let notified = self.notify.notified();
+            let delay = Delay::new(front_deadline.saturating_duration_since(Instant::now()));
+            tokio::select! {
+                _ = notified => {}
+                _ = delay => {}
+            }
+        }
+
+        self.state.lock().unwrap().heap.pop();
+        // Wake everyone else so the new front re-checks whether it's now its turn.
+        self.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn earlier_deadline_goes_first_even_if_registered_second() {
+        let queue = Arc::new(WaiterQueue::new());
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let now = Instant::now();
+
+        let q1 = queue.clone();
+        let o1 = order.clone();
+        let late = tokio::spawn(async move {
+            q1.wait_turn(now + Duration::from_millis(40)).await;
+            o1.lock().unwrap().push("late");
+        });
+
+        // give the first waiter a head start registering before the earlier one
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let q2 = queue.clone();
+        let o2 = order.clone();
+        let early = tokio::spawn(async move {
+            q2.wait_turn(now + Duration::from_millis(10)).await;
+            o2.lock().unwrap().push("early");
+        });
+
+        late.await.unwrap();
+        early.await.unwrap();
+        assert_eq!(*order.lock().unwrap(), vec!["early", "late"]);
+    }
+
+    #[tokio::test]
+    async fn ties_are_broken_by_arrival_order() {
+        let queue = Arc::new(WaiterQueue::new());
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        // far enough out that both waiters are queued on the same deadline at once
+        let deadline = Instant::now() + Duration::from_millis(20);
+
+        let q1 = queue.clone();
+        let o1 = order.clone();
+        let first = tokio::spawn(async move {
+            q1.wait_turn(deadline).await;
+            o1.lock().unwrap().push(1);
+        });
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let q2 = queue.clone();
+        let o2 = order.clone();
+        let second = tokio::spawn(async move {
+            q2.wait_turn(deadline).await;
+            o2.lock().unwrap().push(2);
+        });
+
+        first.await.unwrap();
+        second.await.unwrap();
+        assert_eq!(*order.lock().unwrap(), vec![1, 2]);
+    }
+}